@@ -0,0 +1,68 @@
+use std::process::ExitCode;
+
+use anyhow::{Context, Result};
+use openssh::Session;
+use tokio::{process::Command, signal::unix::SignalKind};
+use tracing::warn;
+
+/// Run `command` on `ssh` in a real remote pty, the way a user dropping into a shell would.
+///
+/// `session.command(...)` has no way to request a pty for a single invocation -- it only builds
+/// the remote command line, not flags for the local `ssh` process carrying it. So for this one
+/// case we fall back to spawning the real `ssh` binary ourselves, but point it at `session`'s own
+/// control socket (`-S`) so it rides the already-authenticated master connection instead of
+/// opening a second, independently-authenticated one. Because it's then a genuine local
+/// `ssh -tt` process with our stdio inherited straight through, SIGWINCH/terminal resize is
+/// handled the same way it is for any other interactive `ssh -t` session: the `ssh` binary
+/// watches our controlling terminal itself and forwards window-change requests to the remote
+/// pty, so there's nothing further for us to wire up by hand.
+///
+/// We still forward Ctrl-C/terminate to the child explicitly, and always make a best-effort
+/// attempt afterwards to kill any remote process whose command line still carries our tag,
+/// regardless of whether it exited cleanly, exited with an error, or we failed to even wait on
+/// it. Processes the command started that don't carry the tag are not killed.
+pub async fn run(session: &Session, ssh: &str, command: &str) -> Result<ExitCode> {
+	// A harmless no-op prefix that tags the remote shell's command line, so we can find and
+	// kill it later if we need to clean up.
+	let tag = format!("nix-remote-{}", std::process::id());
+	let remote_command = format!(": {tag}; {command}");
+
+	let mut child = Command::new("ssh")
+		.arg("-tt")
+		.arg("-S")
+		.arg(session.control_socket())
+		.arg(ssh)
+		.arg(&remote_command)
+		.spawn()
+		.context("failed to spawn interactive ssh")?;
+
+	let mut sigint = tokio::signal::unix::signal(SignalKind::interrupt())?;
+	let mut sigterm = tokio::signal::unix::signal(SignalKind::terminate())?;
+
+	let wait_result = loop {
+		tokio::select! {
+			status = child.wait() => break status.context("waiting on ssh"),
+			_ = sigint.recv() => forward_signal(&child, libc::SIGINT),
+			_ = sigterm.recv() => forward_signal(&child, libc::SIGTERM),
+		}
+	};
+
+	// Best-effort: kills whatever tagged process is still running remotely no matter which path
+	// got us here, instead of only after a clean, successful wait.
+	let _ = session.command("pkill").arg("-f").arg(&tag).status().await;
+
+	let status = wait_result?;
+	if !status.success() {
+		warn!("remote command exited with {status}");
+	}
+
+	Ok(ExitCode::from(status.code().unwrap_or(1) as u8))
+}
+
+fn forward_signal(child: &tokio::process::Child, sig: i32) {
+	let Some(pid) = child.id() else { return };
+	// SAFETY: forwarding a signal to our own direct child by its pid.
+	unsafe {
+		libc::kill(pid as i32, sig);
+	}
+}
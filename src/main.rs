@@ -1,12 +1,12 @@
 use std::{
 	collections::BTreeSet,
-	ffi::OsStr,
-	os::unix::prelude::{OsStrExt, PermissionsExt},
+	os::unix::prelude::PermissionsExt,
 	path::{Path, PathBuf},
 	process::{exit, ExitCode},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
+use futures::stream::{self, StreamExt};
 use openssh::{KnownHosts, Session, Stdio};
 use openssh_sftp_client::{Sftp, SftpOptions};
 use serde::Deserialize;
@@ -15,6 +15,13 @@ use tracing::{error, info, warn};
 
 use clap::Parser;
 
+mod interactive;
+mod probe;
+mod rewrite;
+mod transport;
+
+use transport::Transport;
+
 #[derive(Parser)]
 struct Opts {
 	installable: String,
@@ -22,6 +29,14 @@ struct Opts {
 	// Deduce automatically from installable main attribute?
 	#[clap(short = 'c')]
 	command: String,
+	/// How to deliver the closure to the remote: rewrite store paths and upload over SFTP
+	/// (`remap`), use the remote's own Nix store via `nix copy` (`native`), or pick whichever
+	/// applies (`auto`).
+	#[clap(long, value_enum, default_value_t = Transport::Auto)]
+	transport: Transport,
+	/// Number of store paths to upload concurrently when using the remap transport.
+	#[clap(long, default_value_t = 4)]
+	jobs: usize,
 }
 
 #[derive(Deserialize, Debug)]
@@ -85,6 +100,7 @@ async fn main() -> Result<ExitCode> {
 	};
 	// dbg!(&paths);
 	let paths = paths.into_iter().map(|p| p.path).collect::<Vec<_>>();
+	let max_path_len = paths.iter().map(|p| p.len()).max().unwrap_or(0);
 	let paths_regex = paths
 		.iter()
 		.map(|p| regex::escape(p))
@@ -104,10 +120,77 @@ async fn main() -> Result<ExitCode> {
 
 	info!("initializing SSH");
 	let session = Session::connect(&opts.ssh, KnownHosts::Strict).await?;
+	let remote = probe::probe(&session).await?;
+	info!(
+		"remote is {} ({}@{}), remap root: {}",
+		remote.os,
+		remote.username,
+		remote.uid,
+		remote.remap_root.as_deref().unwrap_or("<none>")
+	);
+
+	let use_native = match opts.transport {
+		Transport::Native => true,
+		Transport::Remap => false,
+		Transport::Auto => remote.native_store_usable,
+	};
+
+	let primary_bin = if use_native {
+		transport::copy_closure_native(&opts.ssh, &opts.installable).await?;
+		PathBuf::from(primary_path.path)
+	} else {
+		let remap_root = remote.remap_root.as_deref().ok_or_else(|| {
+			anyhow!(
+				"remote {} has no writable remap directory of the required length ({} bytes); \
+				 the remap transport cannot be used here (e.g. Windows remotes aren't supported) \
+				 -- try --transport native",
+				remote.os,
+				NIX_STORE.len()
+			)
+		})?;
+		if !(remote.has_chmod && remote.has_ln && remote.has_mkdir) {
+			bail!("remote is missing chmod/ln/mkdir, required for the remap transport");
+		}
+		remap_closure(
+			&session,
+			remap_root,
+			&paths,
+			&paths_regex,
+			max_path_len,
+			&primary_path.path,
+			opts.jobs,
+		)
+		.await?
+	};
+
+	info!("done");
+
+	let remote_command = format!(
+		"export PATH=\"{}/bin:$PATH\"; {}",
+		primary_bin
+			.to_str()
+			.expect("copy will fail if path is not utf-8"),
+		opts.command
+	);
+	interactive::run(&session, &opts.ssh, &remote_command).await
+}
+
+/// Upload the closure to the remote by rewriting store path references byte-for-byte and
+/// transferring the result over SFTP into `remap_root` (see [`probe::probe`]). Returns the
+/// remapped path to the closure's primary output.
+async fn remap_closure(
+	session: &Session,
+	remap_root: &str,
+	paths: &BTreeSet<String>,
+	paths_regex: &regex::bytes::Regex,
+	max_path_len: usize,
+	primary_path: &str,
+	jobs: usize,
+) -> Result<PathBuf> {
 	let output = session
 		.command("mkdir")
 		.arg("-p")
-		.arg(DEFAULT_REMAP)
+		.arg(remap_root)
 		.status()
 		.await?;
 	if !output.success() {
@@ -128,12 +211,13 @@ async fn main() -> Result<ExitCode> {
 	)
 	.await?;
 	let mut fs = sftp.fs();
-	// FIXME: possible vulnerability, anyone can edit root directory itself
-	// ideally this should be per-user directory, maybe in XDG_RUNTIME_DIR
-	let _ = fs.dir_builder().create(DEFAULT_REMAP).await;
+	// `remap_root` is chosen by `probe::probe`: a per-uid `/tmp/nr-<bucket>/` directory first,
+	// falling back to the shared `DEFAULT_REMAP`, so distinct users normally don't share one
+	// directory.
+	let _ = fs.dir_builder().create(remap_root).await;
 
 	let installed_dir = {
-		let mut out = PathBuf::from(DEFAULT_REMAP);
+		let mut out = PathBuf::from(remap_root);
 		out.push("installed");
 		out
 	};
@@ -158,138 +242,155 @@ async fn main() -> Result<ExitCode> {
 		existing
 	};
 
-	let remap_path = |src: &Path| -> Result<PathBuf> {
-		// TODO: support DEFAULT_REMAP with length different from NIX_STORE
-		let src = src.strip_prefix(NIX_STORE)?;
-		let mut remapped = PathBuf::from(DEFAULT_REMAP);
-		remapped.push(src);
-		Ok(remapped)
+	// Exclusively-created lock file: held for the duration of the transfer so two concurrent
+	// `nix-remote` invocations targeting the same remap root don't interleave partial writes.
+	let lock_path = {
+		let mut p = PathBuf::from(remap_root);
+		p.push(".lock");
+		p
 	};
+	let mut lock_file = fs
+		.sftp()
+		.options()
+		.create_new(true)
+		.write(true)
+		.open(&lock_path)
+		.await
+		.with_context(|| {
+			format!(
+				"{lock_path:?} already exists -- another nix-remote transfer looks to be in \
+				 progress against {remap_root}; remove the lock file if that's stale"
+			)
+		})?;
+
+	let to_install = paths.difference(&existing).collect::<Vec<_>>();
+	info!(
+		"installing {} paths with {jobs} concurrent workers",
+		to_install.len()
+	);
+
+	let results: Vec<Result<()>> = stream::iter(to_install)
+		.map(|path| {
+			install_path(
+				session,
+				&sftp,
+				remap_root,
+				&paths_regex,
+				max_path_len,
+				&installed_dir,
+				path,
+			)
+		})
+		.buffer_unordered(jobs.max(1))
+		.collect()
+		.await;
+	let install_result = results.into_iter().try_for_each(|result| result);
+
+	// Release the lock unconditionally, even if a path above failed to upload: leaving it held
+	// after a transient failure would strand every future invocation behind a stale lock file,
+	// which is strictly worse than the failure that caused it.
+	let close_result = lock_file.close().await;
+	let lock_path_str = lock_path
+		.to_str()
+		.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?;
+	let _ = session.command("rm").arg("-f").arg(lock_path_str).status().await;
+
+	install_result?;
+	close_result?;
+
+	remap_into(remap_root, &PathBuf::from(primary_path))
+}
+
+/// Remap a single absolute store path to its location under `remap_root`.
+fn remap_into(remap_root: &str, src: &Path) -> Result<PathBuf> {
+	// remap_root is probed to have the same byte length as NIX_STORE, see probe::probe
+	let src = src.strip_prefix(NIX_STORE)?;
+	let mut remapped = PathBuf::from(remap_root);
+	remapped.push(src);
+	Ok(remapped)
+}
 
-	// TODO: make it atomic/locking
-	// TODO: All sftp communication is sketchy, and works poorly, maybe the helper program will help?
-	for path in paths.difference(&existing) {
-		info!("installing {path}");
-		let mut local_path = PathBuf::from(NIX_STORE);
-		local_path.push(path);
-
-		{
-			let remote_path = remap_path(&local_path)?;
-			if fs.metadata(&remote_path).await.is_ok() {
-				warn!("path exists, that is unexpected, removing");
-				let o = session
-					.command("rm")
-					.arg("-rf")
-					.arg(
-						remote_path
-							.to_str()
-							.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
-					)
-					.status()
-					.await?;
-				if !o.success() {
-					bail!("rm failed for {path:?}");
-				}
+/// Upload a single store path (and its whole subtree) to the remote, writing the
+/// `installed/<path>` marker only once it's fully materialized so an interrupted run can
+/// resume by skipping paths whose marker already exists.
+async fn install_path(
+	session: &Session,
+	sftp: &Sftp,
+	remap_root: &str,
+	paths_regex: &regex::bytes::Regex,
+	max_path_len: usize,
+	installed_dir: &Path,
+	path: &str,
+) -> Result<()> {
+	info!("installing {path}");
+	let mut fs = sftp.fs();
+	let mut local_path = PathBuf::from(NIX_STORE);
+	local_path.push(path);
+
+	{
+		let remote_path = remap_into(remap_root, &local_path)?;
+		if fs.metadata(&remote_path).await.is_ok() {
+			warn!("path exists, that is unexpected, removing");
+			let o = session
+				.command("rm")
+				.arg("-rf")
+				.arg(
+					remote_path
+						.to_str()
+						.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
+				)
+				.status()
+				.await?;
+			if !o.success() {
+				bail!("rm failed for {path:?}");
 			}
 		}
+	}
+
+	let mut permissions = Vec::new();
+	for entry in walkdir::WalkDir::new(&local_path) {
+		let entry = entry?;
+		let mut remote_entry_path = PathBuf::from(remap_root);
+		remote_entry_path.push(entry.path().strip_prefix(NIX_STORE).expect("in nix store"));
+		info!("processing {remote_entry_path:?}");
 
-		let mut permissions = Vec::new();
-		for entry in walkdir::WalkDir::new(&local_path) {
-			let entry = entry?;
-			let mut remote_entry_path = PathBuf::from(DEFAULT_REMAP);
-			remote_entry_path.push(entry.path().strip_prefix(NIX_STORE).expect("in nix store"));
-			info!("processing {remote_entry_path:?}");
-
-			let metadata = entry.metadata()?;
-			if metadata.is_dir() {
-				fs.dir_builder()
-					.create(&remote_entry_path)
-					.await
-					.with_context(|| format!("mkdir failed at {remote_entry_path:?}"))?;
-				permissions.push((remote_entry_path.clone(), metadata.permissions().mode()));
-			} else if metadata.is_file() {
-				let mut remote_file = fs
-					.sftp()
-					.options()
-					.create_new(true)
-					.write(true)
-					// FIXME: there is fileattrs, but they are not exposed in public api
-					.open(&remote_entry_path)
-					.await
-					.with_context(|| format!("create failed at {remote_entry_path:?}"))?;
-
-				let local_file = std::fs::File::open(entry.path())?;
-				if local_file.metadata()?.len() == 0 {
-					remote_file.close().await?;
-					continue;
-				}
-				let local_file = unsafe { memmap::Mmap::map(&local_file) }?;
-				let mut local_file = &local_file as &[u8];
-				while !local_file.is_empty() {
-					if let Some(pos) = paths_regex.find(local_file) {
-						if pos.start() != 0 {
-							remote_file.write_all(&local_file[..pos.start()]).await?;
-						}
-						let path = PathBuf::from(OsStr::from_bytes(pos.as_bytes()));
-						let remapped = remap_path(&path)?;
-						remote_file
-							.write_all(remapped.as_os_str().as_bytes())
-							.await?;
-						local_file = &local_file[pos.end()..];
-					} else {
-						remote_file.write_all(local_file).await?;
-						local_file = &[];
-					}
-				}
+		let metadata = entry.metadata()?;
+		if metadata.is_dir() {
+			fs.dir_builder()
+				.create(&remote_entry_path)
+				.await
+				.with_context(|| format!("mkdir failed at {remote_entry_path:?}"))?;
+			permissions.push((remote_entry_path.clone(), metadata.permissions().mode()));
+		} else if metadata.is_file() {
+			let mut remote_file = fs
+				.sftp()
+				.options()
+				.create_new(true)
+				.write(true)
+				// FIXME: there is fileattrs, but they are not exposed in public api
+				.open(&remote_entry_path)
+				.await
+				.with_context(|| format!("create failed at {remote_entry_path:?}"))?;
+
+			if metadata.len() == 0 {
 				remote_file.close().await?;
-				let o = session
-					.command("chmod")
-					.arg(format!("{:0>3o}", metadata.permissions().mode() & 0o777))
-					.arg(
-						remote_entry_path
-							.to_str()
-							.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
-					)
-					.status()
-					.await?;
-				if !o.success() {
-					bail!("chmod failed for {path:?}");
-				}
-				permissions.push((remote_entry_path.clone(), metadata.permissions().mode()));
-			} else {
-				let link = fs::read_link(entry.path()).await?;
-				let remapped = if link.is_absolute() {
-					remap_path(&link)?
-				} else {
-					link.to_path_buf()
-				};
-				// TODO: sftp api provided by openssh_sftp_client disallows creation of bad symlinks
-				let o = session
-					.command("ln")
-					.arg("-s")
-					.arg(
-						remapped
-							.to_str()
-							.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
-					)
-					.arg(
-						remote_entry_path
-							.to_str()
-							.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
-					)
-					.status()
-					.await?;
-				if !o.success() {
-					bail!("ln failed for {remote_entry_path:?}");
-				}
+				continue;
 			}
-		}
-		for (path, mode) in permissions {
+			rewrite::rewrite_file_streaming(
+				entry.path(),
+				&mut remote_file,
+				paths_regex,
+				|src| remap_into(remap_root, src),
+				max_path_len,
+			)
+			.await?;
+			remote_file.close().await?;
 			let o = session
 				.command("chmod")
-				.arg(format!("{:0>3o}", mode & 0o777))
+				.arg(format!("{:0>3o}", metadata.permissions().mode() & 0o777))
 				.arg(
-					path.to_str()
+					remote_entry_path
+						.to_str()
 						.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
 				)
 				.status()
@@ -297,27 +398,55 @@ async fn main() -> Result<ExitCode> {
 			if !o.success() {
 				bail!("chmod failed for {path:?}");
 			}
+			permissions.push((remote_entry_path.clone(), metadata.permissions().mode()));
+		} else {
+			let link = fs::read_link(entry.path()).await?;
+			let remapped = if link.is_absolute() {
+				remap_into(remap_root, &link)?
+			} else {
+				link.to_path_buf()
+			};
+			// TODO: sftp api provided by openssh_sftp_client disallows creation of bad symlinks
+			let o = session
+				.command("ln")
+				.arg("-s")
+				.arg(
+					remapped
+						.to_str()
+						.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
+				)
+				.arg(
+					remote_entry_path
+						.to_str()
+						.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
+				)
+				.status()
+				.await?;
+			if !o.success() {
+				bail!("ln failed for {remote_entry_path:?}");
+			}
 		}
-		{
-			info!("finalizing");
-			let mut installed = installed_dir.clone();
-			installed.push(path);
-			fs.write(&installed, &[]).await?;
+	}
+	for (entry_path, mode) in permissions {
+		let o = session
+			.command("chmod")
+			.arg(format!("{:0>3o}", mode & 0o777))
+			.arg(
+				entry_path
+					.to_str()
+					.ok_or_else(|| anyhow!("no support for non-utf8 paths"))?,
+			)
+			.status()
+			.await?;
+		if !o.success() {
+			bail!("chmod failed for {entry_path:?}");
 		}
 	}
-
-	info!("done");
-
-	let exec_err = exec::Command::new("ssh")
-		.arg("-t")
-		.arg(opts.ssh)
-		.arg(format!(
-			"export PATH=\"{}/bin:$PATH\"; {}",
-			remap_path(&PathBuf::from(primary_path.path))?
-				.to_str()
-				.expect("copy will fail if path is not utf-8"),
-			opts.command
-		))
-		.exec();
-	Err(exec_err.into())
+	{
+		info!("finalizing {path}");
+		let mut installed = installed_dir.to_path_buf();
+		installed.push(path);
+		fs.write(&installed, &[]).await?;
+	}
+	Ok(())
 }
@@ -0,0 +1,136 @@
+use anyhow::Result;
+use openssh::Session;
+use tracing::info;
+
+use crate::{DEFAULT_REMAP, NIX_STORE};
+
+/// What we learned about the remote before attempting to copy anything to it.
+#[derive(Debug)]
+pub struct RemoteInfo {
+	pub os: String,
+	pub username: String,
+	pub uid: String,
+	pub has_chmod: bool,
+	pub has_ln: bool,
+	pub has_mkdir: bool,
+	/// Whether the chosen remap root was actually writable and of a usable length.
+	/// `None` means the remap transport cannot be used on this remote at all.
+	pub remap_root: Option<String>,
+	/// Whether `/nix/store` is directly writable by us, or the remote has `nix`/`nix-store`
+	/// and a reachable daemon we can `nix copy` through instead.
+	pub native_store_usable: bool,
+}
+
+/// Probe the remote for the capabilities both transports depend on: OS/user identity,
+/// presence of `chmod`/`ln`/`mkdir`, a writable remap root of the right length, and whether
+/// the remote's own Nix store is usable directly. One pass, so neither transport needs to
+/// re-query the remote for information this already gathered.
+pub async fn probe(session: &Session) -> Result<RemoteInfo> {
+	let os = run(session, "uname -s")
+		.await
+		.unwrap_or_else(|| "unknown".to_owned());
+	let username = run(session, "id -un").await.unwrap_or_else(|| "unknown".to_owned());
+	let uid = run(session, "id -u").await.unwrap_or_else(|| "unknown".to_owned());
+
+	let has_chmod = has_command(session, "chmod").await;
+	let has_ln = has_command(session, "ln").await;
+	let has_mkdir = has_command(session, "mkdir").await;
+	let has_nix_store = has_command(session, "nix-store").await;
+	let has_nix = has_command(session, "nix").await;
+
+	let mut remap_root = None;
+	if has_mkdir {
+		for candidate in remap_root_candidates(&uid) {
+			if writable(session, &candidate).await {
+				remap_root = Some(candidate);
+				break;
+			}
+		}
+	}
+
+	// A standard multi-user install has a root-owned, non-writable /nix/store, but `nix copy`
+	// still works through the daemon -- so store writability alone would miss the common case.
+	let native_store_usable = writable(session, NIX_STORE).await
+		|| (has_nix_store && has_nix && nix_daemon_reachable(session).await);
+
+	let info = RemoteInfo {
+		os,
+		username,
+		uid,
+		has_chmod,
+		has_ln,
+		has_mkdir,
+		remap_root,
+		native_store_usable,
+	};
+	info!(?info, "probed remote");
+	Ok(info)
+}
+
+/// Build a list of remap root candidates, tried in order, each exactly [`NIX_STORE`]'s length
+/// in bytes: some closures embed store paths in fixed-width fields (ELF rpaths chief among
+/// them), so a replacement root of a different length would corrupt them in place.
+///
+/// The real per-user directories we'd actually want (`$HOME/.cache/nixrm/`,
+/// `$XDG_RUNTIME_DIR/nixrm/`) are essentially always longer than that budget, so instead of
+/// mangling them down to size (which lands mid-path-component and resolves to nothing useful),
+/// we derive a short, fixed-width, per-uid bucket under `/tmp` -- distinct users land in
+/// distinct directories instead of racing over one shared `DEFAULT_REMAP`.
+fn remap_root_candidates(uid: &str) -> Vec<String> {
+	vec![format!("/tmp/nr-{}/", uid_bucket(uid)), DEFAULT_REMAP.to_owned()]
+}
+
+/// Hash `uid` down to a 2-character base36 bucket, so `/tmp/nr-<bucket>/` is always exactly
+/// [`NIX_STORE`]'s length in bytes regardless of how many digits the uid itself has.
+fn uid_bucket(uid: &str) -> String {
+	const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+	let hash = uid.bytes().fold(0u32, |acc, b| acc.wrapping_mul(31).wrapping_add(b as u32));
+	let n = hash % (ALPHABET.len() as u32 * ALPHABET.len() as u32);
+	let hi = ALPHABET[(n / ALPHABET.len() as u32) as usize] as char;
+	let lo = ALPHABET[(n % ALPHABET.len() as u32) as usize] as char;
+	format!("{hi}{lo}")
+}
+
+async fn nix_daemon_reachable(session: &Session) -> bool {
+	session
+		.command("sh")
+		.arg("-c")
+		.arg(
+			"nix store ping --store daemon >/dev/null 2>&1 || \
+			 test -S /nix/var/nix/daemon-socket/socket",
+		)
+		.status()
+		.await
+		.map(|s| s.success())
+		.unwrap_or(false)
+}
+
+async fn run(session: &Session, cmd: &str) -> Option<String> {
+	let output = session.command("sh").arg("-c").arg(cmd).output().await.ok()?;
+	if !output.status.success() {
+		return None;
+	}
+	Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+async fn has_command(session: &Session, name: &str) -> bool {
+	session
+		.command("sh")
+		.arg("-c")
+		.arg(format!("command -v {name}"))
+		.status()
+		.await
+		.map(|s| s.success())
+		.unwrap_or(false)
+}
+
+async fn writable(session: &Session, dir: &str) -> bool {
+	session
+		.command("sh")
+		.arg("-c")
+		.arg(format!("mkdir -p {dir} && test -w {dir}"))
+		.status()
+		.await
+		.map(|s| s.success())
+		.unwrap_or(false)
+}
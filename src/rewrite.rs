@@ -0,0 +1,82 @@
+use std::{
+	ffi::OsStr,
+	os::unix::prelude::OsStrExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use openssh_sftp_client::file::File as SftpFile;
+use tokio::io::AsyncReadExt;
+
+// Read blocks this size at a time, instead of mmap-ing the whole store path into memory.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Stream `local_path` into `remote_file`, rewriting any occurrence of a closure store path
+/// found by `paths_regex` via `remap_path`, without ever holding the whole file in memory.
+///
+/// `max_path_len` is the byte length of the longest store path string that can appear in
+/// `paths_regex`; we always keep at least `max_path_len - 1` trailing bytes of the previous
+/// block around ("carry") so a match straddling a chunk boundary is never missed.
+pub async fn rewrite_file_streaming(
+	local_path: &Path,
+	remote_file: &mut SftpFile,
+	paths_regex: &regex::bytes::Regex,
+	remap_path: impl Fn(&Path) -> Result<PathBuf>,
+	max_path_len: usize,
+) -> Result<()> {
+	let mut local_file = tokio::fs::File::open(local_path).await?;
+	let mut chunk = vec![0u8; CHUNK_SIZE];
+	let mut carry: Vec<u8> = Vec::new();
+	let mut buf = Vec::with_capacity(CHUNK_SIZE + max_path_len);
+
+	loop {
+		let n = local_file.read(&mut chunk).await?;
+		let eof = n == 0;
+
+		buf.clear();
+		buf.extend_from_slice(&carry);
+		buf.extend_from_slice(&chunk[..n]);
+
+		// Bytes past `safe_len` might still be the prefix of a match that continues into the
+		// next block, so they're never written out directly; at EOF there is no next block.
+		let safe_len = if eof {
+			buf.len()
+		} else {
+			buf.len().saturating_sub(max_path_len.saturating_sub(1))
+		};
+
+		let mut cursor = 0usize;
+		let mut limit = safe_len;
+		for m in paths_regex.find_iter(&buf) {
+			if m.start() >= safe_len {
+				break;
+			}
+			if m.end() > safe_len {
+				// Straddles the boundary: defer the whole match to the next block.
+				limit = m.start();
+				break;
+			}
+			if m.start() > cursor {
+				remote_file.write_all(&buf[cursor..m.start()]).await?;
+			}
+			let matched = PathBuf::from(OsStr::from_bytes(m.as_bytes()));
+			let remapped = remap_path(&matched)?;
+			remote_file
+				.write_all(remapped.as_os_str().as_bytes())
+				.await?;
+			cursor = m.end();
+		}
+		if cursor < limit {
+			remote_file.write_all(&buf[cursor..limit]).await?;
+			cursor = limit;
+		}
+
+		carry = buf[cursor..].to_vec();
+
+		if eof {
+			break;
+		}
+	}
+
+	Ok(())
+}
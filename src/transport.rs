@@ -0,0 +1,30 @@
+use anyhow::{ensure, Result};
+use clap::ValueEnum;
+use tokio::process::Command;
+use tracing::info;
+
+/// Selects how the closure is delivered to the remote host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+	/// Always rewrite store path references and upload via SFTP (current behavior).
+	Remap,
+	/// Always use the remote's own Nix store via `nix copy`.
+	Native,
+	/// Use the native store when the remote has one we can write to, otherwise remap.
+	Auto,
+}
+
+/// Copy the closure of `installable` to `ssh` using Nix's own export/import mechanism,
+/// the same way `nix-copy-closure` does, instead of the byte-level remap.
+pub async fn copy_closure_native(ssh: &str, installable: &str) -> Result<()> {
+	info!("copying closure via nix copy (native store transport)");
+	let status = Command::new("nix")
+		.arg("copy")
+		.arg("--to")
+		.arg(format!("ssh://{ssh}"))
+		.arg(installable)
+		.status()
+		.await?;
+	ensure!(status.success(), "nix copy --to ssh://{ssh} failed");
+	Ok(())
+}